@@ -1,38 +1,218 @@
 
 use std::rc::Rc;
-use ThunkCell;
+use BoxThunk;
 
-struct LazyList<T, Fh, Ft>
-    where Fh: FnOnce() -> T,
-          Ft: FnOnce() -> LazyList<T, Fh, Ft>
-{
-    head: Rc<ThunkCell<T, Fh>>,
-    tail: Rc<ThunkCell<LazyList<T, Fh, Ft>, Ft>>,
+// A lazy, possibly-infinite stream. Each node holds a memoized head thunk and
+// a memoized tail thunk; the tail forces to `None` at the end of a finite
+// list. Nodes are shared behind `Rc`, so cloning a list (or walking it with an
+// iterator) reuses the already-forced prefix instead of recomputing it. The
+// type-erased BoxThunk lets combinators like `map` return a uniform type.
+type HeadThunk<T> = BoxThunk<T>;
+type TailThunk<T> = BoxThunk<Option<LazyList<T>>>;
+
+pub struct LazyList<T> {
+    head: Rc<HeadThunk<T>>,
+    tail: Rc<TailThunk<T>>,
+}
+
+impl<T> Clone for LazyList<T> {
+    fn clone(&self) -> Self {
+        LazyList{ head: Rc::clone(&self.head), tail: Rc::clone(&self.tail) }
+    }
 }
 
-struct Repeat<T, F>(Rc<ThunkCell<T, F>>) where F: FnOnce() -> T;
+impl<T: 'static> LazyList<T> {
+    // build a node from a head thunk and a tail thunk
+    fn from_parts<Fh, Ft>(head: Fh, tail: Ft) -> Self
+        where Fh: FnOnce() -> T + 'static,
+              Ft: FnOnce() -> Option<LazyList<T>> + 'static,
+    {
+        LazyList{
+            head: Rc::new(BoxThunk::new(head)),
+            tail: Rc::new(BoxThunk::new(tail)),
+        }
+    }
 
-impl<T, F> FnOnce<()> for Repeat<T, F>
-    where F: FnOnce() -> T
-{
-    type Output = LazyList<T, F, Repeat<T, F>>;
-    extern "rust-call" fn call_once(self, args: ()) -> Self::Output {
+    // cons an already-known head value in front of a lazily computed tail
+    pub fn cons<Ft>(head: T, tail: Ft) -> Self
+        where Ft: FnOnce() -> Option<LazyList<T>> + 'static
+    {
         LazyList{
-            head: Rc::clone(&self.0),
-            tail: Rc::new(ThunkCell::new(self)),
+            head: Rc::new(BoxThunk::value(head)),
+            tail: Rc::new(BoxThunk::new(tail)),
         }
     }
+
+    // reuse an existing (shared, possibly already-forced) head thunk
+    fn from_shared<Ft>(head: Rc<HeadThunk<T>>, tail: Ft) -> Self
+        where Ft: FnOnce() -> Option<LazyList<T>> + 'static
+    {
+        LazyList{ head: head, tail: Rc::new(BoxThunk::new(tail)) }
+    }
+
+    // finite list from any iterator; an empty iterator yields `None`
+    pub fn from_iter<I>(iter: I) -> Option<Self>
+        where I: IntoIterator<Item = T>,
+              I::IntoIter: 'static,
+    {
+        let mut it = iter.into_iter();
+        match it.next() {
+            None => None,
+            Some(head) => Some(LazyList::cons(head, move || LazyList::from_iter(it))),
+        }
+    }
+
+    // infinite stream x, f(x), f(f(x)), ...
+    pub fn iterate<F>(seed: T, f: F) -> Self
+        where F: Fn(&T) -> T + 'static,
+              T: Clone,
+    {
+        LazyList::iterate_rc(seed, Rc::new(f))
+    }
+
+    fn iterate_rc<F>(seed: T, f: Rc<F>) -> Self
+        where F: Fn(&T) -> T + 'static,
+              T: Clone,
+    {
+        let next_seed = seed.clone();
+        LazyList::cons(seed, move || {
+            let next = f(&next_seed);
+            Some(LazyList::iterate_rc(next, f))
+        })
+    }
+
+    // infinite stream repeating a single value
+    pub fn repeat(value: T) -> Self where T: Clone {
+        LazyList::iterate(value, |x| x.clone())
+    }
+
+    // lazily map every element through `f`
+    pub fn map<U, F>(self, f: F) -> LazyList<U>
+        where F: Fn(T) -> U + 'static,
+              T: Clone,
+              U: 'static,
+    {
+        LazyList::map_rc(self, Rc::new(f))
+    }
+
+    fn map_rc<U, F>(list: LazyList<T>, f: Rc<F>) -> LazyList<U>
+        where F: Fn(T) -> U + 'static,
+              T: Clone,
+              U: 'static,
+    {
+        let head_src = list.clone();
+        let head_fn = Rc::clone(&f);
+        let head = move || head_fn((**head_src.head).clone());
+        let tail = move || (**list.tail).clone().map(|t| LazyList::map_rc(t, f));
+        LazyList::from_parts(head, tail)
+    }
+
+    // lazily keep only elements satisfying `f`
+    pub fn filter<F>(self, f: F) -> Option<LazyList<T>>
+        where F: Fn(&T) -> bool + 'static,
+              T: Clone,
+    {
+        LazyList::filter_rc(Some(self), Rc::new(f))
+    }
+
+    fn filter_rc<F>(mut cur: Option<LazyList<T>>, f: Rc<F>) -> Option<LazyList<T>>
+        where F: Fn(&T) -> bool + 'static,
+              T: Clone,
+    {
+        while let Some(list) = cur {
+            if f(&**list.head) {
+                let head = (**list.head).clone();
+                return Some(LazyList::cons(head, move || {
+                    LazyList::filter_rc((**list.tail).clone(), f)
+                }));
+            }
+            cur = (**list.tail).clone();
+        }
+        None
+    }
+
+    // take at most `n` elements, producing a finite list (sharing the prefix)
+    pub fn take(self, n: usize) -> Option<LazyList<T>> {
+        if n == 0 {
+            return None;
+        }
+        let tail = Rc::clone(&self.tail);
+        Some(LazyList::from_shared(self.head, move || {
+            (**tail).clone().and_then(|t| t.take(n - 1))
+        }))
+    }
+}
+
+// An iterator that walks a list by cloning the shared tail and forcing each
+// head. Because the nodes are memoized, a consumed prefix stays cached for
+// other iterators over the same (shared) list.
+pub struct Iter<T> {
+    next: Option<LazyList<T>>,
+}
+
+impl<T: Clone + 'static> Iterator for Iter<T> {
+    type Item = T;
+    fn next(&mut self) -> Option<T> {
+        match self.next.take() {
+            None => None,
+            Some(list) => {
+                let value = (**list.head).clone();
+                self.next = (**list.tail).clone();
+                Some(value)
+            },
+        }
+    }
+}
+
+impl<T: Clone + 'static> IntoIterator for LazyList<T> {
+    type Item = T;
+    type IntoIter = Iter<T>;
+    fn into_iter(self) -> Iter<T> {
+        Iter{ next: Some(self) }
+    }
+}
+
+impl<'a, T: Clone + 'static> IntoIterator for &'a LazyList<T> {
+    type Item = T;
+    type IntoIter = Iter<T>;
+    fn into_iter(self) -> Iter<T> {
+        Iter{ next: Some(self.clone()) }
+    }
 }
 
 #[test]
 fn lazy_list() {
+    let nats = LazyList::iterate(0u64, |x| x + 1);
+    let first_five: Vec<u64> = nats.take(5).unwrap().into_iter().collect();
+    assert_eq!(first_five, vec![0, 1, 2, 3, 4]);
+}
+
+#[test]
+fn shared_prefix_is_memoized() {
     use std::cell::Cell;
-    let mut counter = Cell::new(0);
-    let five = || { counter.set(1+counter.get()); 5 };
-    let fives = Repeat(Rc::new(ThunkCell::new(five)))();
+
+    let counter = Rc::new(Cell::new(0));
+    let c = Rc::clone(&counter);
+    let list = LazyList::cons(1, move || {
+        c.set(c.get() + 1);
+        Some(LazyList::cons(2, || None))
+    });
     assert_eq!(counter.get(), 0);
-    assert_eq!(**fives.head, 5);
+
+    let first: Vec<i32> = (&list).into_iter().collect();
+    assert_eq!(first, vec![1, 2]);
     assert_eq!(counter.get(), 1);
-    assert_eq!(**fives.tail.tail.tail.tail.tail.tail.head, 5);
+
+    // second traversal reuses the memoized tail node, so it is not re-run
+    let second: Vec<i32> = (&list).into_iter().collect();
+    assert_eq!(second, vec![1, 2]);
     assert_eq!(counter.get(), 1);
 }
+
+#[test]
+fn map_and_filter() {
+    let nats = LazyList::iterate(0u64, |x| x + 1);
+    let evens = nats.map(|x| x * 2).filter(|x| *x > 0).unwrap();
+    let taken: Vec<u64> = evens.take(3).unwrap().into_iter().collect();
+    assert_eq!(taken, vec![2, 4, 6]);
+}
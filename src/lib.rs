@@ -4,18 +4,26 @@
 use std::cell;
 use std::mem;
 use std::ops;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
 
 pub enum LazyOption<T, F> where F: FnOnce() -> T {
     Empty,
+    Blackhole,
     Function(F),
     Result(T),
 }
 
+// Returned when forcing a cell that is defined in terms of itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CycleError;
+
 impl<T, F> LazyOption<T, F> where F: FnOnce() -> T {
     pub fn into_result(self) -> Option<T> {
         match self {
             LazyOption::Empty => None,
+            LazyOption::Blackhole => None,
             LazyOption::Function(f) => Some(f()),
             LazyOption::Result(x) => Some(x),
         }
@@ -41,19 +49,77 @@ impl<T, F> LazyCell<T, F> where F: FnOnce() -> T {
 
     // in this function we use the UnsafeCell like a Cell
     pub fn evaluate(&self) {
+        self.try_force().expect("infinite recursion while forcing thunk");
+    }
+
+    // Force without panicking on a cycle. Returns the memoized value, or
+    // CycleError if the cell is found to depend on itself (the Blackhole
+    // state). Still panics if the cell is Empty (its value was taken).
+    pub fn try_force(&self) -> Result<&T, CycleError> {
+        unsafe {
+            match &*self.inner.get() {
+                &LazyOption::Result(_) => {},
+                &LazyOption::Blackhole => return Err(CycleError),
+                &LazyOption::Empty => panic!("Deref on empty LazyCell"),
+                &LazyOption::Function(_) => {
+                    // park a Blackhole while we run the closure, so that a
+                    // re-entrant force observes the cycle instead of Empty
+                    let mut dance = LazyOption::Blackhole;
+                    mem::swap(&mut *self.inner.get(), &mut dance); // like a Cell
+                    if let LazyOption::Function(f) = dance {
+                        *self.inner.get() = LazyOption::Result(f());
+                    } else {
+                        unreachable!();
+                    }
+                },
+            }
+            if let &LazyOption::Result(ref v) = &*self.inner.get() {
+                Ok(v)
+            } else {
+                unreachable!();
+            }
+        }
+    }
+
+    // Peek at the value without forcing: Some only if already computed.
+    pub fn get(&self) -> Option<&T> {
+        unsafe {
+            if let &LazyOption::Result(ref v) = &*self.inner.get() {
+                Some(v)
+            } else {
+                None
+            }
+        }
+    }
+
+    // Seed a value, but only if the cell has not been forced yet (still holds
+    // its function). On any other state the value is handed back unchanged.
+    pub fn set(&self, value: T) -> Result<(), T> {
         unsafe {
-            if let &LazyOption::Function(_) = &*self.inner.get() {
-                let mut dance = LazyOption::Empty;
-                mem::swap(&mut *self.inner.get(), &mut dance); // like a Cell
-                if let LazyOption::Function(f) = dance {
-                    *self.inner.get() = LazyOption::Result(f());
-                } else {
-                    unreachable!();
-                }
+            match &*self.inner.get() {
+                &LazyOption::Function(_) => {
+                    *self.inner.get() = LazyOption::Result(value);
+                    Ok(())
+                },
+                _ => Err(value),
             }
         }
     }
 
+    // Move the computed value out, resetting the cell to Empty. Returns None
+    // if the cell has not been forced to a value.
+    pub fn take(&mut self) -> Option<T> {
+        let mut dance = LazyOption::Empty;
+        mem::swap(unsafe { &mut *self.inner.get() }, &mut dance);
+        match dance {
+            LazyOption::Result(v) => Some(v),
+            other => {
+                unsafe { *self.inner.get() = other; }
+                None
+            },
+        }
+    }
+
     // borrow a LazyCell as an Fn closure
     pub fn cache_fn<'a>(&'a self) -> impl Fn() -> &'a T {
         move || &*self
@@ -87,6 +153,103 @@ impl<T, F: FnOnce() -> T> ops::DerefMut for LazyCell<T, F> {
 }
 
 
+// A thread-safe analogue of LazyCell, initialized exactly once under
+// contention. Built on an atomic state word rather than an unsynchronized
+// UnsafeCell, so a lazily-initialized value can back global/static data shared
+// across threads. Concurrent derefs block (spinning with a yield) until the
+// single initializing thread publishes the value.
+const SYNC_INCOMPLETE: usize = 0;
+const SYNC_RUNNING: usize = 1;
+const SYNC_COMPLETE: usize = 2;
+
+pub struct SyncLazyCell<T, F> where F: FnOnce() -> T {
+    state: AtomicUsize,
+    func: cell::UnsafeCell<Option<F>>,
+    value: cell::UnsafeCell<Option<T>>,
+}
+
+// Safe because the atomic state word gates every access: the value is only
+// written by the thread that wins the INCOMPLETE -> RUNNING transition, and is
+// only read once it has been published (COMPLETE).
+unsafe impl<T, F> Sync for SyncLazyCell<T, F>
+    where T: Send + Sync, F: Send + FnOnce() -> T {}
+
+// If the initializing closure panics we roll the state back to INCOMPLETE
+// rather than leaving it stuck at RUNNING forever (which would deadlock every
+// waiter). The consumed FnOnce cannot be restored, so re-forcing a cell
+// poisoned this way reports that distinctly.
+struct SyncResetGuard<'a> {
+    state: &'a AtomicUsize,
+    armed: bool,
+}
+
+impl<'a> SyncResetGuard<'a> {
+    fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl<'a> Drop for SyncResetGuard<'a> {
+    fn drop(&mut self) {
+        if self.armed {
+            self.state.store(SYNC_INCOMPLETE, Ordering::Release);
+        }
+    }
+}
+
+impl<T, F> SyncLazyCell<T, F> where F: FnOnce() -> T {
+    pub fn new(func: F) -> Self {
+        SyncLazyCell {
+            state: AtomicUsize::new(SYNC_INCOMPLETE),
+            func: cell::UnsafeCell::new(Some(func)),
+            value: cell::UnsafeCell::new(None),
+        }
+    }
+
+    // Force the value, blocking if another thread is initializing.
+    pub fn evaluate(&self) -> &T {
+        loop {
+            match self.state.load(Ordering::Acquire) {
+                SYNC_COMPLETE => return self.get().expect("published value missing"),
+                SYNC_RUNNING => thread::yield_now(),
+                _ => {
+                    let won = self.state
+                        .compare_exchange(SYNC_INCOMPLETE, SYNC_RUNNING,
+                                          Ordering::Acquire, Ordering::Acquire)
+                        .is_ok();
+                    if won {
+                        let guard = SyncResetGuard{ state: &self.state, armed: true };
+                        let func = unsafe { (*self.func.get()).take() }
+                            .expect("SyncLazyCell poisoned by panicking initializer");
+                        let result = func();
+                        unsafe { *self.value.get() = Some(result); }
+                        guard.disarm();
+                        self.state.store(SYNC_COMPLETE, Ordering::Release);
+                        return self.get().expect("published value missing");
+                    }
+                },
+            }
+        }
+    }
+
+    // Non-forcing peek: Some only once the value has been published.
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == SYNC_COMPLETE {
+            unsafe { (*self.value.get()).as_ref() }
+        } else {
+            None
+        }
+    }
+}
+
+impl<T, F: FnOnce() -> T> ops::Deref for SyncLazyCell<T, F> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.evaluate()
+    }
+}
+
+
 #[cfg(test)]
 mod tests {
     use std::cell;
@@ -100,4 +263,12 @@ mod tests {
         assert_eq!(*val, 7);
         assert!(called.get());
     }
+
+    #[test]
+    fn sync_forces_once() {
+        let val = SyncLazyCell::new(|| 7);
+        assert!(val.get().is_none());
+        assert_eq!(*val, 7);
+        assert_eq!(val.get(), Some(&7));
+    }
 }
@@ -1,32 +1,79 @@
+// This module provides open-recursion memoization: the memoized function is
+// handed a reference back to the Memo so that it can request its own
+// subproblems, which are cached and shared as Rc<Value>.
 
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::rc::Rc;
 
-struct Memo<Args, T, Call>
-    where Args: Hash,
-          Call: MemoFn<Args=Args, Value=T>,
-{
-    call: Call,
-    items: HashMap<Args, Rc<ThunkCell<T, MemoThunk<Args, T, Call>>>>,
+
+// A function to be memoized. `call` receives a handle to the owning Memo, so
+// the body can recurse into memo.get(..) for subproblems.
+pub trait MemoFn: Sized {
+    type Args: Hash + Eq + Clone;
+    type Value;
+    fn call(&self, memo: &Memo<Self>, args: Self::Args) -> Self::Value;
+}
+
+// A cache slot. InProgress is the blackhole: observing it means the value is
+// being defined in terms of itself, which is an illegal cyclic memoization.
+enum MemoSlot<T> {
+    InProgress,
+    Ready(Rc<T>),
 }
 
-struct MemoThunk<'f, Args, T, F>
-    where F: Fn(Args) -> T + 'f,
-{
-    func: &'f F,
-    args: Args,
+pub struct Memo<Call> where Call: MemoFn {
+    call: Call,
+    items: RefCell<HashMap<Call::Args, MemoSlot<Call::Value>>>,
 }
 
+impl<Call> Memo<Call> where Call: MemoFn {
+    pub fn new(call: Call) -> Self {
+        Memo{ call: call, items: RefCell::new(HashMap::new()) }
+    }
 
-impl FnOnce for MemoThunk<Args, T, F> {
-    type Output = T;
-    extern "rust-call" fn call_once(self, args: ()) -> T {
-        self.func(self.args)
+    // Fetch the memoized result for `args`, computing it on a miss. The result
+    // is shared: repeated and recursive calls return the same Rc.
+    pub fn get(&self, args: Call::Args) -> Rc<Call::Value> {
+        if let Some(slot) = self.items.borrow().get(&args) {
+            match *slot {
+                MemoSlot::Ready(ref value) => return Rc::clone(value),
+                MemoSlot::InProgress =>
+                    panic!("infinite recursion while memoizing"),
+            }
+        }
+        // mark the key as in-progress before recursing, so a re-entrant get
+        // for the same args trips the blackhole instead of looping forever
+        self.items.borrow_mut().insert(args.clone(), MemoSlot::InProgress);
+        let value = Rc::new(self.call.call(self, args.clone()));
+        self.items.borrow_mut().insert(args, MemoSlot::Ready(Rc::clone(&value)));
+        value
+    }
+
+    // Non-forcing check for whether `args` has already been computed.
+    pub fn contains(&self, args: &Call::Args) -> bool {
+        match self.items.borrow().get(args) {
+            Some(&MemoSlot::Ready(_)) => true,
+            _ => false,
+        }
     }
 }
 
 #[test]
 fn memoize() {
-    let memo = RefCell::new(None);
-    let fact = |memo, n| n * memo.borrow_mut().unwrap().unwrap()[n - 1];
-    *memo.borrow_mut().unwrap() = Some(Memo{fact, HashMap::new()});
-    assert_eq!(fact(5), 120);
+    struct Fact;
+    impl MemoFn for Fact {
+        type Args = u64;
+        type Value = u64;
+        fn call(&self, memo: &Memo<Self>, n: u64) -> u64 {
+            if n == 0 { 1 } else { n * *memo.get(n - 1) }
+        }
+    }
+
+    let memo = Memo::new(Fact);
+    assert_eq!(*memo.get(5), 120);
+    for n in 0..=5 {
+        assert!(memo.contains(&n));
+    }
 }
@@ -4,21 +4,31 @@
 use std::cell;
 use std::mem;
 use std::ops;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::thread;
 
 
-// We use this enum to store our thunk/result,
-// but can also use the Empty variant for swapping,
-// when we want to consume the FnOnce from inside a mutable reference
+// We use this enum to store our thunk/result.
+// Empty is reserved for the consumed/moved-out case, where the computed
+// value has been moved out (see take) and nothing was put back.
+// Blackhole marks a thunk that is currently being forced: if we observe it
+// we know the value is defined in terms of itself.
 pub enum ThunkEnum<T, F> where F: FnOnce() -> T {
     Empty,
+    Blackhole,
     Function(F),
     Value(T),
 }
 
+// Returned when forcing a thunk that is defined in terms of itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CycleError;
+
 impl<T, F> ThunkEnum<T, F> where F: FnOnce() -> T {
     pub fn into_value(self) -> Option<T> {
         match self {
             ThunkEnum::Empty => None,
+            ThunkEnum::Blackhole => None,
             ThunkEnum::Function(f) => Some(f()),
             ThunkEnum::Value(x) => Some(x),
         }
@@ -50,19 +60,76 @@ impl<T, F> ThunkCell<T, F> where F: FnOnce() -> T {
 
     // in this function we use the UnsafeCell like a Cell
     pub fn evaluate(&self) {
+        self.try_force().expect("infinite recursion while forcing thunk");
+    }
+
+    // Force without panicking on a cycle. Returns the memoized value, or
+    // CycleError if the thunk is found to depend on itself (the Blackhole
+    // state). Still panics if the cell is Empty (its value was taken).
+    pub fn try_force(&self) -> Result<&T, CycleError> {
+        unsafe {
+            match &*self.inner.get() {
+                &ThunkEnum::Value(_) => {},
+                &ThunkEnum::Blackhole => return Err(CycleError),
+                &ThunkEnum::Empty => panic!("Deref on empty ThunkCell"),
+                &ThunkEnum::Function(_) => {
+                    // park a Blackhole while we run the closure, so that a
+                    // re-entrant force observes the cycle instead of Empty
+                    let mut dance = ThunkEnum::Blackhole;
+                    mem::swap(&mut *self.inner.get(), &mut dance); // like a Cell
+                    if let ThunkEnum::Function(f) = dance {
+                        *self.inner.get() = ThunkEnum::Value(f());
+                    } else {
+                        unreachable!();
+                    }
+                },
+            }
+            if let &ThunkEnum::Value(ref v) = &*self.inner.get() {
+                Ok(v)
+            } else {
+                unreachable!();
+            }
+        }
+    }
+
+    // Peek at the value without forcing: Some only if already computed.
+    pub fn get(&self) -> Option<&T> {
+        unsafe {
+            if let &ThunkEnum::Value(ref v) = &*self.inner.get() {
+                Some(v)
+            } else {
+                None
+            }
+        }
+    }
+
+    // Seed a value, but only if the cell has not been forced yet (still holds
+    // its function). On any other state the value is handed back unchanged.
+    pub fn set(&self, value: T) -> Result<(), T> {
         unsafe {
-            if let &ThunkEnum::Function(_) = &*self.inner.get() {
-                let mut dance = ThunkEnum::Empty;
-                // since we checked that it was in the function state first
-                mem::swap(&mut *self.inner.get(), &mut dance); // like a Cell
-                if let ThunkEnum::Function(f) = dance {
-                    *self.inner.get() = ThunkEnum::Value(f());
-                } else {
-                    unreachable!();
-                }
+            match &*self.inner.get() {
+                &ThunkEnum::Function(_) => {
+                    *self.inner.get() = ThunkEnum::Value(value);
+                    Ok(())
+                },
+                _ => Err(value),
             }
         }
     }
+
+    // Move the computed value out, resetting the cell to Empty. Returns None
+    // if the cell has not been forced to a value.
+    pub fn take(&mut self) -> Option<T> {
+        let mut dance = ThunkEnum::Empty;
+        mem::swap(unsafe { &mut *self.inner.get() }, &mut dance);
+        match dance {
+            ThunkEnum::Value(v) => Some(v),
+            other => {
+                unsafe { *self.inner.get() = other; }
+                None
+            },
+        }
+    }
 }
 
 impl<T, F> ops::Deref for ThunkCell<T, F>
@@ -104,12 +171,70 @@ impl<T, F> Into<T> for ThunkCell<T, F>
                 ThunkEnum::Function(f) => f(),
                 ThunkEnum::Value(value) => value,
                 ThunkEnum::Empty => panic!("Unwrapped empty ThunkCell"),
+                ThunkEnum::Blackhole => panic!("Unwrapped blackholed ThunkCell"),
             }
         }
     }
 }
 
 
+// A type-erased thunk: the closure is hidden behind a boxed trait object, so
+// differently-built thunks share one type and a combinator can return a thunk
+// without naming its closure. It reuses ThunkCell's four-state machine (and
+// therefore its Blackhole cycle detection) via the boxed FnOnce.
+pub struct BoxThunk<T> {
+    inner: ThunkCell<T, Box<dyn FnOnce() -> T>>,
+}
+
+impl<T> BoxThunk<T> {
+    pub fn new<F>(func: F) -> Self where F: FnOnce() -> T + 'static {
+        BoxThunk{ inner: ThunkCell::new(Box::new(func)) }
+    }
+
+    pub fn value(value: T) -> Self {
+        BoxThunk{ inner: ThunkCell::value(value) }
+    }
+
+    pub fn evaluate(&self) {
+        self.inner.evaluate();
+    }
+
+    pub fn try_force(&self) -> Result<&T, CycleError> {
+        self.inner.try_force()
+    }
+}
+
+impl<T> ops::Deref for BoxThunk<T> {
+    type Target = T;
+    fn deref(&self) -> &T {
+        &*self.inner
+    }
+}
+
+impl<T> ops::DerefMut for BoxThunk<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut *self.inner
+    }
+}
+
+impl<T, F> From<ThunkCell<T, F>> for BoxThunk<T>
+    where F: FnOnce() -> T + 'static,
+{
+    fn from(cell: ThunkCell<T, F>) -> Self {
+        let erased = unsafe {
+            match cell.inner.into_inner() {
+                ThunkEnum::Function(f) =>
+                    ThunkEnum::Function(Box::new(f) as Box<dyn FnOnce() -> T>),
+                ThunkEnum::Value(v) => ThunkEnum::Value(v),
+                ThunkEnum::Empty => ThunkEnum::Empty,
+                ThunkEnum::Blackhole => ThunkEnum::Blackhole,
+            }
+        };
+        BoxThunk{ inner: ThunkCell{ inner: cell::UnsafeCell::new(erased) } }
+    }
+}
+
+
 // like ThunkCell, but with normal mut semantics
 // using this may help the optimizer rearrange the code it is used in.
 // removing interior mutability defeats the purpose of a lot of use-cases though
@@ -134,10 +259,11 @@ impl<T, F> ThunkMut<T, F> where F: FnOnce() -> T {
     }
 
     pub fn evaluate(&mut self) {
-        let mut dance = ThunkEnum::Empty;
+        let mut dance = ThunkEnum::Blackhole;
         mem::swap(&mut self.inner, &mut dance);
         self.inner = match dance {
             ThunkEnum::Function(f) => ThunkEnum::Value(f()),
+            ThunkEnum::Blackhole => panic!("infinite recursion while forcing thunk"),
             value => value,
         }
     }
@@ -152,6 +278,7 @@ impl<T, F> Into<T> for ThunkMut<T, F>
             ThunkEnum::Function(f) => f(),
             ThunkEnum::Value(value) => value,
             ThunkEnum::Empty => panic!("Unwrapped empty ThunkMut"),
+            ThunkEnum::Blackhole => panic!("Unwrapped blackholed ThunkMut"),
         }
     }
 }
@@ -181,3 +308,101 @@ impl<T, F> From<ThunkCell<T, F>> for ThunkMut<T, F>
 }
 
 
+
+// A thread-safe analogue of ThunkCell, initialized exactly once under
+// contention. Built on an atomic state word rather than interior-mutable
+// UnsafeCell-with-no-synchronization, so the forced value can be shared across
+// threads. Concurrent derefs block (spinning with a yield) until the single
+// initializing thread publishes the value.
+const INCOMPLETE: usize = 0;
+const RUNNING: usize = 1;
+const COMPLETE: usize = 2;
+
+pub struct SyncThunkCell<T, F> where F: FnOnce() -> T {
+    state: AtomicUsize,
+    func: cell::UnsafeCell<Option<F>>,
+    value: cell::UnsafeCell<Option<T>>,
+}
+
+// Safe because the atomic state word gates every access: the value is only
+// touched mutably by the single thread that wins the INCOMPLETE -> RUNNING
+// transition, and is only read once it has been published (COMPLETE).
+unsafe impl<T, F> Sync for SyncThunkCell<T, F>
+    where T: Send + Sync, F: Send + FnOnce() -> T {}
+
+// If the initializing closure panics we roll the state back to INCOMPLETE
+// rather than leaving it stuck at RUNNING forever (which would deadlock every
+// waiter). The consumed FnOnce cannot be restored, so a later force of a
+// poisoned-by-panic cell reports that distinctly.
+struct ResetGuard<'a> {
+    state: &'a AtomicUsize,
+    armed: bool,
+}
+
+impl<'a> ResetGuard<'a> {
+    fn disarm(mut self) {
+        self.armed = false;
+    }
+}
+
+impl<'a> Drop for ResetGuard<'a> {
+    fn drop(&mut self) {
+        if self.armed {
+            self.state.store(INCOMPLETE, Ordering::Release);
+        }
+    }
+}
+
+impl<T, F> SyncThunkCell<T, F> where F: FnOnce() -> T {
+    pub fn new(func: F) -> Self {
+        SyncThunkCell {
+            state: AtomicUsize::new(INCOMPLETE),
+            func: cell::UnsafeCell::new(Some(func)),
+            value: cell::UnsafeCell::new(None),
+        }
+    }
+
+    // Force the value, blocking if another thread is initializing.
+    pub fn evaluate(&self) -> &T {
+        loop {
+            match self.state.load(Ordering::Acquire) {
+                COMPLETE => return self.get().expect("published value missing"),
+                RUNNING => thread::yield_now(),
+                _ => {
+                    let won = self.state
+                        .compare_exchange(INCOMPLETE, RUNNING,
+                                          Ordering::Acquire, Ordering::Acquire)
+                        .is_ok();
+                    if won {
+                        let guard = ResetGuard{ state: &self.state, armed: true };
+                        let func = unsafe { (*self.func.get()).take() }
+                            .expect("SyncThunkCell poisoned by panicking initializer");
+                        let result = func();
+                        unsafe { *self.value.get() = Some(result); }
+                        guard.disarm();
+                        self.state.store(COMPLETE, Ordering::Release);
+                        return self.get().expect("published value missing");
+                    }
+                },
+            }
+        }
+    }
+
+    // Non-forcing peek: Some only once the value has been published.
+    pub fn get(&self) -> Option<&T> {
+        if self.state.load(Ordering::Acquire) == COMPLETE {
+            unsafe { (*self.value.get()).as_ref() }
+        } else {
+            None
+        }
+    }
+}
+
+impl<T, F> ops::Deref for SyncThunkCell<T, F>
+    where F: FnOnce() -> T,
+{
+    type Target = T;
+    fn deref(&self) -> &T {
+        self.evaluate()
+    }
+}